@@ -100,6 +100,57 @@ impl FrozenEnv {
   #[must_use] pub fn get_atom(&self, s: &[u8]) -> Option<AtomId> { unsafe { self.thaw() }.atoms.get(s).copied() }
   /// Accessor for [`Environment::pe`]
   #[must_use] pub fn pe(&self) -> &ParserEnv { &unsafe { self.thaw() }.pe }
+
+  /// Get all the MMC compilers that are reachable from the lisp globals. The compiler
+  /// does not have a fixed home in the environment, and `compiler.mm1` keeps it
+  /// hidden in the closures of `mmc-add` and `mmc-finish`, so we search through
+  /// lists, maps, refs and the captured variables of lambdas to find it.
+  #[cfg(feature = "mmc")]
+  #[must_use] pub fn mmc_compilers(&self) -> Vec<&crate::mmc::Compiler> {
+    use std::collections::HashSet;
+    fn walk<'a>(e: &'a FrozenLispKind,
+      seen: &mut HashSet<*const FrozenLispKind>, out: &mut Vec<&'a crate::mmc::Compiler>
+    ) {
+      // Refs and closures can form cycles, so we only visit each value once
+      if !seen.insert(e) { return }
+      match e {
+        FrozenLispKind::List(es) => for e in &**es { walk(e, seen, out) },
+        FrozenLispKind::DottedList(es, r) => {
+          for e in &**es { walk(e, seen, out) }
+          walk(r, seen, out)
+        }
+        FrozenLispKind::Annot(_, e) |
+        FrozenLispKind::Goal(e) => walk(e, seen, out),
+        FrozenLispKind::AtomMap(m) => for e in m.values() { walk(e, seen, out) },
+        FrozenLispKind::Ref(m) => if let Some(e) = m.get() { walk(e, seen, out) },
+        // Safety: we only read through the thawed reference, and do not clone it.
+        FrozenLispKind::Proc(p) => match unsafe { p.thaw() } {
+          Proc::Lambda {env, ..} => for v in &**env {
+            // Safety: the environment is frozen, so the captured values
+            // will not be modified while this reference is alive.
+            walk(unsafe { v.freeze() }, seen, out)
+          },
+          Proc::MmcCompiler(c) =>
+            // Safety: the compiler is only mutably borrowed during evaluation,
+            // which has finished because the environment is frozen.
+            if let Ok(c) = unsafe { c.try_borrow_unguarded() } { out.push(c) },
+          _ => {}
+        },
+        FrozenLispKind::Atom(_) |
+        FrozenLispKind::Number(_) |
+        FrozenLispKind::String(_) |
+        FrozenLispKind::Bool(_) |
+        FrozenLispKind::Syntax(_) |
+        FrozenLispKind::Undef |
+        FrozenLispKind::MVar(_, _) => {}
+      }
+    }
+    let (mut seen, mut out) = (HashSet::new(), vec![]);
+    for ad in self.data().iter() {
+      if let Some(ld) = ad.lisp() { walk(ld, &mut seen, &mut out) }
+    }
+    out
+  }
 }
 
 /// A wrapper around an [`AtomData`] that is frozen.
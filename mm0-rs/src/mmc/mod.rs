@@ -96,6 +96,9 @@ impl Compiler {
     }
   }
 
+  /// The map of atoms for defined entities (operations and types).
+  #[must_use] pub fn names(&self) -> &HashMap<AtomId, Entity> { &self.names }
+
   /// Add the given MMC text (as a list of lisp literals) to the compiler state,
   /// performing typehecking but not code generation. This can be called multiple
   /// times to add multiple functions, but each lisp literal is already a list of
@@ -1,5 +1,6 @@
 //! MMC name resolution pass.
 use std::collections::{HashMap, hash_map::Entry};
+use std::convert::TryInto;
 use crate::{elab::Result, ElabError, AtomId, FileSpan};
 use super::{Compiler, types};
 use types::Spanned;
@@ -27,8 +28,11 @@ impl Compiler {
       }
     }
     match &item.k {
-      ItemKind::Proc(proc) => add_item(names, proc.name.k, &proc.name.span,
-        || Entity::Proc(Spanned {span: item.span.clone(), k: ProcTc::Unchecked}))?,
+      ItemKind::Proc(proc) => add_item(names, proc.name.k, &proc.name.span, || {
+        let tyargs = proc.tyargs.len().try_into().expect("too many type arguments");
+        let args = proc.args.len().try_into().expect("too many arguments");
+        Entity::Proc(Spanned {span: item.span.clone(), k: ProcTc::Unchecked(proc.kind, tyargs, args)})
+      })?,
       ItemKind::Global(lhs, _) => lhs.on_names(&mut |sp, v| {
         add_item(names, v, sp, || Entity::Global(Spanned {span: sp.clone(), k: GlobalTc::Unchecked}))
       })?,
@@ -641,6 +641,17 @@ pub enum ProcKind {
 }
 crate::deep_size_0!(ProcKind);
 
+impl ProcKind {
+  /// The keyword used to declare a procedure of this kind.
+  #[must_use] pub fn to_str(self) -> &'static str {
+    match self {
+      ProcKind::Func => "func",
+      ProcKind::Proc => "proc",
+      ProcKind::Intrinsic(_) => "intrinsic",
+    }
+  }
+}
+
 /// A return value, after resolving `mut` / `out` annotations.
 #[derive(Debug, DeepSizeOf)]
 pub enum Ret {
@@ -304,7 +304,10 @@ impl Remap for TypeTy {
 #[derive(Debug, DeepSizeOf)]
 pub enum ProcTc {
   /// We have determined that this is a procedure but we have not yet examined the body.
-  Unchecked,
+  /// `Unchecked(kind, tyargs, args)` records the kind of the procedure and the number of
+  /// type arguments and arguments, as written in the AST, so that the procedure can still
+  /// be described if typechecking fails.
+  Unchecked(ast::ProcKind, u32, u32),
   /// We have determined the type of the procedure.
   Typed(ProcTy, Option<mir::Proc>),
 }
@@ -313,7 +316,7 @@ impl ProcTc {
   /// Get the type of the procedure, if it has been deduced.
   #[must_use] pub fn ty(&self) -> Option<&ProcTy> {
     match self {
-      ProcTc::Unchecked => None,
+      ProcTc::Unchecked(..) => None,
       ProcTc::Typed(ty, _) => Some(ty)
     }
   }
@@ -321,7 +324,7 @@ impl ProcTc {
   /// Get the compiled MIR for the procedure, if it has been deduced.
   #[must_use] pub fn mir(&self) -> Option<&mir::Proc> {
     match self {
-      ProcTc::Unchecked => None,
+      ProcTc::Unchecked(..) => None,
       ProcTc::Typed(_, mir) => mir.as_ref()
     }
   }
@@ -331,7 +334,7 @@ impl Remap for ProcTc {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self {
     match self {
-      ProcTc::Unchecked => ProcTc::Unchecked,
+      &ProcTc::Unchecked(kind, tyargs, args) => ProcTc::Unchecked(kind, tyargs, args),
       ProcTc::Typed(ty, mir) => ProcTc::Typed(ty.remap(r), mir.remap(r))
     }
   }
@@ -158,6 +158,12 @@ impl Default for Size {
 }
 
 impl Size {
+  /// All the sizes, in increasing order.
+  const ALL: [Size; 5] = [Size::S8, Size::S16, Size::S32, Size::S64, Size::Inf];
+
+  /// Iterate over all the sizes, in increasing order.
+  #[must_use] pub fn all() -> impl Iterator<Item=Size> + Clone { Self::ALL.iter().copied() }
+
   /// The number of bits of this type, or `None` for the infinite case.
   #[must_use] pub fn bits(self) -> Option<u8> {
     match self {
@@ -200,6 +206,12 @@ impl std::fmt::Display for IntTy {
 }
 
 impl IntTy {
+  /// Iterate over all the integral types: first the signed types and then the
+  /// unsigned types, each in order of increasing size.
+  #[must_use] pub fn all() -> impl Iterator<Item=IntTy> + Clone {
+    Size::all().map(IntTy::Int).chain(Size::all().map(IntTy::UInt))
+  }
+
   /// The size of this integral type.
   #[must_use] pub fn size(self) -> Size {
     match self { IntTy::Int(sz) | IntTy::UInt(sz) => sz }
@@ -631,3 +643,41 @@ impl<T: EnvDisplay> EnvDisplay for Mm0Expr<T> {
     Mm0ExprNodePrint(&self.subst, &self.expr).fmt(fe, f)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{IntTy, Size, entity::PrimType};
+
+  #[test]
+  fn all_sizes() {
+    assert_eq!(Size::all().count(), 5);
+    assert!(Size::all().zip(Size::all().skip(1)).all(|(a, b)| a < b));
+  }
+
+  /// The integral type named by a primitive type, as in `Parser::parse_ty`.
+  fn prim_int_ty(prim: PrimType) -> Option<IntTy> {
+    Some(match prim {
+      PrimType::I8 => IntTy::Int(Size::S8),
+      PrimType::I16 => IntTy::Int(Size::S16),
+      PrimType::I32 => IntTy::Int(Size::S32),
+      PrimType::I64 => IntTy::Int(Size::S64),
+      PrimType::Int => IntTy::Int(Size::Inf),
+      PrimType::U8 => IntTy::UInt(Size::S8),
+      PrimType::U16 => IntTy::UInt(Size::S16),
+      PrimType::U32 => IntTy::UInt(Size::S32),
+      PrimType::U64 => IntTy::UInt(Size::S64),
+      PrimType::Nat => IntTy::UInt(Size::Inf),
+      _ => return None,
+    })
+  }
+
+  #[test]
+  fn int_ty_round_trip() {
+    let all = IntTy::all().collect::<Vec<_>>();
+    assert_eq!(all.len(), 10);
+    for (i, &ity) in all.iter().enumerate() {
+      assert!(!all[..i].contains(&ity), "duplicate {}", ity);
+      assert_eq!(PrimType::from_str(ity.to_str()).and_then(prim_int_ty), Some(ity));
+    }
+  }
+}
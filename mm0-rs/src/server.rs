@@ -13,10 +13,9 @@ use futures::executor::ThreadPool;
 use futures::lock::Mutex as FMutex;
 use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError,
   Request, RequestId, Response, ResponseError};
-use serde::ser::Serialize;
 use serde_json::{from_value, to_value};
 use serde_repr::{Serialize_repr, Deserialize_repr};
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 #[allow(clippy::wildcard_imports)] use lsp_types::*;
 use crossbeam::channel::{SendError, RecvError};
 use clap::ArgMatches;
@@ -150,6 +149,59 @@ macro_rules! log {
 
 async fn elaborate(path: FileRef, start: Option<Position>,
     cancel: Arc<AtomicBool>, rd: ArcList<FileRef>) -> Result<ElabResult<u64>> {
+  match std::panic::AssertUnwindSafe(elaborate_core(path.clone(), start, cancel.clone(), rd))
+      .catch_unwind().await {
+    Ok(res) => res,
+    Err(_) => {
+      if recover_from_panic(&path, &cancel).await {
+        Job::Elaborate(path, ElabReason::Recover).spawn()
+      }
+      Err("server panic".into())
+    }
+  }
+}
+
+/// Clear the poison on a lock. This is only correct for locks whose data is never left
+/// in an inconsistent state by a panic. We use it for:
+///
+/// * the [`Vfs`] map and [`VirtualFile::downstream`], which are only modified by single
+///   `insert` and `remove` calls, and a panic while holding them comes from a failed
+///   lookup (like `update_downstream` on a missing file) which does not modify the data;
+/// * [`VirtualFile::text`], which is only modified by assigning a new version and
+///   contents, and a panic while holding it (like calling `ascii()` on a binary file, or
+///   applying a bad change) happens before the assignment.
+///
+/// All other locks are still accessed with [`MutexExt::ulock`], which propagates the panic.
+fn clear_poison<T>(m: &Mutex<T>) { m.clear_poison() }
+
+/// Recover from a panic during the elaboration of `path` by the job with the given
+/// cancel flag. The panicking job may have poisoned the locks it was holding, and it
+/// may have left the file's cache in the [`InProgress`](FileCache::InProgress) state,
+/// in which case every later request for this version of the file would wait
+/// forever on a job that no longer exists. We clear the poison and reset the cache
+/// (which cancels any requests that were waiting on the job).
+///
+/// Returns true if the file should be elaborated again, which is the case if we reset
+/// the cache and the last attempt did not also panic. If the cache belongs to another
+/// job or holds a finished result, then there is nothing to redo.
+async fn recover_from_panic(path: &FileRef, cancel: &Arc<AtomicBool>) -> bool {
+  clear_poison(&SERVER.vfs.0);
+  let file = if let Some(file) = SERVER.vfs.get(path) { file } else { return false };
+  log!("warning: elaboration of {:?} panicked", path);
+  clear_poison(&file.text);
+  clear_poison(&file.downstream);
+  let mut g = file.parsed.lock().await;
+  if matches!(&*g, Some(FileCache::InProgress {cancel: c, ..}) if Arc::ptr_eq(c, cancel)) {
+    log!("resetting file state of {:?}", path);
+    *g = None;
+    !file.panicked.swap(true, Ordering::SeqCst)
+  } else {
+    false
+  }
+}
+
+async fn elaborate_core(path: FileRef, start: Option<Position>,
+    cancel: Arc<AtomicBool>, rd: ArcList<FileRef>) -> Result<ElabResult<u64>> {
   let vfs = &SERVER.vfs;
   debug_assert!(!rd.contains(&path));
   let (path, file) = vfs.get_or_insert(path)?;
@@ -331,6 +383,7 @@ async fn elaborate(path: FileRef, start: Option<Position>,
   if !is_canceled {
     *g = Some(FileCache::Ready {hash, source, ast, res: res.clone(), deps});
     drop(g);
+    file.panicked.store(false, Ordering::SeqCst);
     for d in file.downstream.ulock().iter() {
       log!("{:?} affects {:?}", path, d);
       Job::DepChange(path.clone(), d.clone(), DepChangeReason::Elab).spawn();
@@ -389,6 +442,8 @@ struct VirtualFile {
   parsed: FMutex<Option<FileCache>>,
   /// Files that depend on this one
   downstream: Mutex<HashSet<FileRef>>,
+  /// True if the last elaboration of this file panicked
+  panicked: AtomicBool,
 }
 
 impl VirtualFile {
@@ -396,7 +451,8 @@ impl VirtualFile {
     VirtualFile {
       text: Mutex::new((version, text)),
       parsed: FMutex::new(None),
-      downstream: Mutex::new(HashSet::new())
+      downstream: Mutex::new(HashSet::new()),
+      panicked: AtomicBool::new(false),
     }
   }
 }
@@ -486,6 +542,8 @@ enum RequestType {
   DocumentSymbol(DocumentSymbolParams),
   References(ReferenceParams),
   DocumentHighlight(DocumentHighlightParams),
+  #[cfg(feature = "mmc")]
+  ListProcs(ListProcsParams),
 }
 
 fn parse_request(Request {id, method, params}: Request) -> Result<Option<(RequestId, RequestType)>> {
@@ -497,6 +555,8 @@ fn parse_request(Request {id, method, params}: Request) -> Result<Option<(Reques
     "textDocument/documentSymbol"    => Some((id, RequestType::DocumentSymbol(from_value(params)?))),
     "textDocument/references"        => Some((id, RequestType::References(from_value(params)?))),
     "textDocument/documentHighlight" => Some((id, RequestType::DocumentHighlight(from_value(params)?))),
+    #[cfg(feature = "mmc")]
+    "mmc/listProcs"                  => Some((id, RequestType::ListProcs(from_value(params)?))),
     _ => None
   })
 }
@@ -584,6 +644,9 @@ impl RequestHandler {
         self.finish(references(file.clone(), doc.position, true,
           |range| DocumentHighlight { range, kind: None }).await)
       }
+      #[cfg(feature = "mmc")]
+      RequestType::ListProcs(ListProcsParams {uri}) =>
+        self.finish(list_procs(uri.into()).await),
     }
   }
 
@@ -962,6 +1025,83 @@ async fn document_symbol(path: FileRef) -> StdResult<DocumentSymbolResponse, Res
   Ok(DocumentSymbolResponse::Nested(res))
 }
 
+/// The parameters to the custom `mmc/listProcs` request.
+#[cfg(feature = "mmc")]
+#[derive(Debug, Deserialize)]
+struct ListProcsParams {
+  /// The file whose procedures should be listed.
+  uri: Url,
+}
+
+/// An entry in the response to `mmc/listProcs`, describing one MMC procedure.
+#[cfg(feature = "mmc")]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcInfo {
+  /// The name of the procedure.
+  name: String,
+  /// The kind of procedure: `func`, `proc`, or `intrinsic`.
+  kind: &'static str,
+  /// The range of the declaration in the file.
+  range: Range,
+  /// The number of type arguments.
+  tyargs: u32,
+  /// The number of (non-type) input arguments.
+  num_args: u32,
+}
+
+/// Handler for the custom `mmc/listProcs` request, which lists the procedures
+/// declared in a file, in order of appearance. Procedures that failed to typecheck are
+/// described using the kind and argument counts from the AST.
+///
+/// The procedures are read from the elaborated environment, so this returns an empty
+/// list for binary files and for files that could not be elaborated because of an
+/// import cycle.
+#[cfg(feature = "mmc")]
+async fn list_procs(path: FileRef) -> StdResult<Vec<ProcInfo>, ResponseError> {
+  use std::convert::TryInto;
+  use crate::mmc::types::{Spanned, entity::{Entity, ProcTc}};
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "list procs nonexistent file"))?;
+
+  let maybe_old = if SERVER.elab_on().unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
+  let (text, env) = if let Some((contents, frozen)) = maybe_old {
+    (contents.try_ascii().cloned(), frozen)
+  } else {
+    let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+      .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+    match env.into_response_error()? {
+      None => return Ok(vec![]),
+      Some((_, env)) => (file.text.ulock().1.try_ascii().cloned(), env)
+    }
+  };
+  let text = if let Some(text) = text { text } else { return Ok(vec![]) };
+  let mut res = vec![];
+  for c in env.mmc_compilers() {
+    for (&a, ent) in c.names() {
+      if let Entity::Proc(Spanned {span, k}) = ent {
+        if span.file == path {
+          let (kind, tyargs, num_args) = match *k {
+            ProcTc::Unchecked(kind, tyargs, args) => (kind, tyargs, args),
+            ProcTc::Typed(ref ty, _) =>
+              (ty.kind, ty.tyargs, ty.args.len().try_into().expect("too many arguments")),
+          };
+          res.push((span.span, ProcInfo {
+            name: String::from_utf8_lossy(env.data()[a].name()).into(),
+            kind: kind.to_str(),
+            range: text.to_range(span.span),
+            tyargs,
+            num_args,
+          }))
+        }
+      }
+    }
+  }
+  // Procedures generated by the same lisp call share a span, so break ties by name
+  res.sort_by(|(sp1, p1), (sp2, p2)| (sp1.start, &p1.name).cmp(&(sp2.start, &p2.name)));
+  Ok(res.into_iter().map(|(_, info)| info).collect())
+}
+
 #[derive(Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 enum TraceKind {Sort, Decl, Global}
@@ -1265,14 +1405,14 @@ impl std::fmt::Display for DepChangeReason {
 }
 
 #[derive(Debug)]
-enum ElabReason { Open, Save, Change(Position) }
+enum ElabReason { Open, Save, Change(Position), Recover }
 
 impl ElabReason {
   fn start(&self) -> Option<Position> {
     match *self {
       Self::Change(p) => Some(p),
       Self::Open => Some(Position::default()),
-      Self::Save => None
+      Self::Save | Self::Recover => None
     }
   }
 }
@@ -1283,6 +1423,7 @@ impl std::fmt::Display for ElabReason {
       Self::Open => write!(f, "open"),
       Self::Save => write!(f, "save"),
       Self::Change(_) => write!(f, "change"),
+      Self::Recover => write!(f, "recovery"),
     }
   }
 }
@@ -1301,6 +1442,7 @@ impl std::fmt::Display for Job {
       Job::Elaborate(path, ElabReason::Open) => write!(f, "elaborate {} on open", path),
       Job::Elaborate(path, ElabReason::Save) => write!(f, "elaborate {} on save", path),
       Job::Elaborate(path, ElabReason::Change(_)) => write!(f, "elaborate {} on change", path),
+      Job::Elaborate(path, ElabReason::Recover) => write!(f, "elaborate {} after panic", path),
       Job::ElaborateDep(from, to, _) => write!(f, "elaborate {} needed for {}", from, to),
       Job::DepChange(from, to, reason) => write!(f, "elaborate {} for {} {}", to, from, reason),
     }
@@ -1415,6 +1557,21 @@ fn send_config_request() -> Result<()> {
 
 impl Server {
   fn new() -> Result<Server> {
+    let (conn, caps) = Self::connect()?;
+    Ok(Server {
+      caps: Mutex::new(caps),
+      conn,
+      reqs: Mutex::new(HashMap::new()),
+      vfs: Vfs(Mutex::new(HashMap::new())),
+      pool: ThreadPool::new()?,
+      threads: Default::default(),
+      options: Mutex::new(ServerOptions::default()),
+    })
+  }
+
+  /// Connect to the client over stdin and stdout, and perform the LSP handshake.
+  #[cfg(not(test))]
+  fn connect() -> Result<(Connection, ClientCapabilities)> {
     let (conn, _iot) = Connection::stdio();
     let params = from_value(conn.initialize(
       to_value(ServerCapabilities {
@@ -1431,15 +1588,18 @@ impl Server {
         ..Default::default()
       })?
     )?)?;
-    Ok(Server {
-      caps: Mutex::new(ClientCapabilities::new(params)),
-      conn,
-      reqs: Mutex::new(HashMap::new()),
-      vfs: Vfs(Mutex::new(HashMap::new())),
-      pool: ThreadPool::new()?,
-      threads: Default::default(),
-      options: Mutex::new(ServerOptions::default()),
-    })
+    Ok((conn, ClientCapabilities::new(params)))
+  }
+
+  /// In tests there is no client, so we use an in-memory connection and a client
+  /// with no capabilities. The client end is leaked, so that sending messages
+  /// succeeds and they are never read.
+  #[cfg(test)]
+  fn connect() -> Result<(Connection, ClientCapabilities)> {
+    let (conn, client) = Connection::memory();
+    std::mem::forget(client);
+    let params = from_value(serde_json::json!({"capabilities": {}}))?;
+    Ok((conn, ClientCapabilities::new(params)))
   }
 
   fn elab_on(&self) -> Option<ElabOn> {
@@ -1509,8 +1669,8 @@ impl Server {
                   let start = {
                     let file = vfs.get(&path).ok_or("changed nonexistent file")?;
                     let (version, text) = &mut *file.text.ulock();
-                    *version = Some(doc.version);
                     let (start, s) = text.ascii().apply_changes(content_changes.into_iter());
+                    *version = Some(doc.version);
                     *text = FileContents::Ascii(Arc::new(s));
                     start
                   };
@@ -1592,3 +1752,127 @@ pub fn main(args: &ArgMatches<'_>) {
   std::mem::take(&mut *server.reqs.ulock());
   std::mem::take(&mut *server.vfs.0.ulock());
 }
+
+#[cfg(test)]
+mod tests {
+  use futures::executor::block_on;
+  use futures::channel::oneshot::{Canceled, Receiver};
+  use super::*;
+
+  /// Add a virtual file with the given contents to the server, without elaborating it.
+  fn open(name: &str, text: &str) -> (FileRef, Arc<VirtualFile>) {
+    let path = FileRef::from(std::env::temp_dir().join(name));
+    let file = Arc::new(VirtualFile::new(Some(1), FileContents::new(text.into())));
+    SERVER.vfs.0.ulock().insert(path.clone(), file.clone());
+    (path, file)
+  }
+
+  /// Poison a lock, in the same way as a job that panics while holding it.
+  fn poison<T>(m: &Mutex<T>) {
+    drop(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+      let _g = m.ulock();
+      panic!("poisoning lock")
+    })));
+    assert!(m.is_poisoned());
+  }
+
+  /// Put the file's cache in the state left behind by a job with the given cancel
+  /// flag that panicked during elaboration, and return a request waiting on the job.
+  fn in_progress(file: &VirtualFile, cancel: &Arc<AtomicBool>) -> Receiver<ElabResult<u64>> {
+    let (send, recv) = channel();
+    *block_on(file.parsed.lock()) = Some(FileCache::InProgress {
+      old: None, version: Some(1), cancel: cancel.clone(), senders: vec![send]
+    });
+    recv
+  }
+
+  #[test]
+  fn recover_from_poisoned_file() {
+    let (path, file) = open("recover_poisoned.mm1", "sort foo;\n");
+    poison(&file.text);
+    poison(&file.downstream);
+    let cancel = Arc::default();
+    let recv = in_progress(&file, &cancel);
+    assert!(block_on(recover_from_panic(&path, &cancel)));
+    assert!(!file.text.is_poisoned());
+    assert!(!file.downstream.is_poisoned());
+    assert!(block_on(file.parsed.lock()).is_none());
+    assert!(matches!(block_on(recv), Err(Canceled)));
+    let res = block_on(elaborate(path, Some(Position::default()), Default::default(), Default::default()));
+    assert!(matches!(res, Ok(ElabResult::Ok(..))));
+    assert!(!file.panicked.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn recover_retries_once() {
+    let (path, file) = open("recover_retries.mm1", "sort foo;\n");
+    let cancel = Arc::default();
+    drop(in_progress(&file, &cancel));
+    assert!(block_on(recover_from_panic(&path, &cancel)));
+    // a second panic in a row does not retry again
+    let cancel = Arc::default();
+    drop(in_progress(&file, &cancel));
+    assert!(!block_on(recover_from_panic(&path, &cancel)));
+    assert!(block_on(file.parsed.lock()).is_none());
+    // a panic in a job that does not own the cache leaves it alone
+    drop(in_progress(&file, &Arc::default()));
+    assert!(!block_on(recover_from_panic(&path, &Arc::default())));
+    assert!(block_on(file.parsed.lock()).is_some());
+    // a successful elaboration allows the next panic to retry
+    *block_on(file.parsed.lock()) = None;
+    let res = block_on(elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default()));
+    assert!(matches!(res, Ok(ElabResult::Ok(..))));
+    let cancel = Arc::default();
+    drop(in_progress(&file, &cancel));
+    assert!(block_on(recover_from_panic(&path, &cancel)));
+  }
+
+  #[cfg(feature = "mmc")]
+  #[test]
+  fn list_procs_kinds_and_args() {
+    let (path, _) = open("list_procs.mm1", "\
+do {
+  (def mmc-add
+    (def c (mmc-init))
+    (fn xs (apply c '+ xs)))
+  (mmc-add '(
+    (intrinsic (sys_open {fname : u32} : u32))
+    (func (id {x : u8} : u8) x)
+    (proc (foo T {x : u8} {y : u8}))))
+};
+");
+    let procs = block_on(list_procs(path)).expect("list procs failed");
+    let procs = procs.iter().map(|p| (&*p.name, p.kind, p.tyargs, p.num_args)).collect::<Vec<_>>();
+    assert_eq!(procs, [
+      ("sys_open", "intrinsic", 0, 1),
+      ("id", "func", 0, 1),
+      ("foo", "proc", 1, 2),
+    ]);
+  }
+
+  #[cfg(feature = "mmc")]
+  #[test]
+  fn list_procs_same_span_sorted_by_name() {
+    let (path, _) = open("list_procs_same_span.mm1", "\
+do {
+  (def mmc-add
+    (def c (mmc-init))
+    (fn xs (apply c '+ xs)))
+  (mmc-add (map (fn (x) '(func (,x {a : u8} : u8) a)) '(c b a)))
+};
+");
+    let procs = block_on(list_procs(path)).expect("list procs failed");
+    let names = procs.iter().map(|p| &*p.name).collect::<Vec<_>>();
+    assert_eq!(names, ["a", "b", "c"]);
+  }
+
+  #[cfg(feature = "mmc")]
+  #[test]
+  fn list_procs_binary_file() {
+    let path = FileRef::from(std::env::temp_dir().join("list_procs_binary.mmb"));
+    let file = Arc::new(VirtualFile::new(Some(1), FileContents::new_bin(Box::new([]))));
+    SERVER.vfs.0.ulock().insert(path.clone(), file.clone());
+    assert!(block_on(list_procs(path)).expect("list procs failed").is_empty());
+    assert!(!file.text.is_poisoned());
+  }
+}